@@ -4,18 +4,21 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use neqo_common::now;
-use neqo_crypto::init;
+use neqo_common::{now, IpTos};
+use neqo_crypto::{init, init_db};
 //use neqo_transport::frame::StreamType;
-use neqo_http3::{Http3Connection, Http3Event};
+use neqo_http3::{Http3Connection, Http3Event, Http3Server, Http3ServerEvent};
 use neqo_transport::frame::StreamType;
+use neqo_transport::server::{ActiveConnectionRef, Server, ServerEvent};
 use neqo_transport::{Connection, ConnectionEvent, Datagram, State};
+use mio::{Events, Interest, Poll, Token};
 use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-// use std::path::PathBuf;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::string::ParseError;
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
@@ -34,6 +37,30 @@ struct Args {
 
     #[structopt(short = "T", long)]
     exclude_tests: Vec<String>,
+
+    /// Run as the responder side of the interop matrix instead of as a client.
+    /// Binds this address and answers QUIC/HTTP3 requests until killed.
+    #[structopt(long)]
+    listen: Option<SocketAddr>,
+
+    /// NSS DB directory holding the cert/key used by `--listen`.
+    #[structopt(long, default_value = "./test_fixture")]
+    db: PathBuf,
+
+    /// Nickname of the cert in `--db` to present when running as a server.
+    #[structopt(long, default_value = "interop")]
+    cert: String,
+
+    /// Base64-encoded ECHConfigList to offer, for peers that don't have one
+    /// of their own configured.
+    #[structopt(long)]
+    ech: Option<String>,
+
+    /// JSON file listing peers to run against, each with `label`, `host`,
+    /// `port`, and optional `alpn`/`ech`/`disabled_tests`. Defaults to the
+    /// public interop grid baked into `default_peers()`.
+    #[structopt(long)]
+    peers: Option<PathBuf>,
 }
 
 trait Handler {
@@ -49,71 +76,81 @@ fn emit_packets(socket: &UdpSocket, out_dgrams: &Vec<Datagram>) {
     }
 }
 
-fn process_loop(
-    nctx: &NetworkCtx,
-    client: &mut Connection,
-    handler: &mut Handler,
-    timeout: &Duration,
-) -> Result<neqo_transport::connection::State, String> {
-    let buf = &mut [0u8; 2048];
-    let mut in_dgrams = Vec::new();
-    let start = Instant::now();
-
-    loop {
-        client.process_input(in_dgrams.drain(..), now());
-
-        if let State::Closed(..) = client.state() {
-            return Ok(client.state().clone());
-        }
+// ECN/IP-TOS plumbing for the `ecn` interop test. Linux-only: asks the
+// kernel to hand back the inbound IP_TOS byte as ancillary data on every
+// `recvmsg`, and sets the outbound IP_TOS byte (whose low two bits are
+// the ECN codepoint) for everything this socket sends from here on.
+fn enable_ecn_reporting<S: AsRawFd>(socket: &S) {
+    let on: libc::c_int = 1;
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_RECVTOS,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rv != 0 {
+        eprintln!("Unable to enable IP_RECVTOS: {:?}", std::io::Error::last_os_error());
+    }
+}
 
-        let exiting = !handler.handle(client);
-        let (out_dgrams, _timer) = client.process_output(now());
-        emit_packets(&nctx.socket, &out_dgrams);
+fn set_outgoing_ecn<S: AsRawFd>(socket: &S, tos: IpTos) {
+    let val: libc::c_int = u8::from(tos).into();
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_TOS,
+            &val as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rv != 0 {
+        eprintln!("Unable to set IP_TOS: {:?}", std::io::Error::last_os_error());
+    }
+}
 
-        if exiting {
-            return Ok(client.state().clone());
-        }
+// Like `UdpSocket::recv`, but also returns the IP_TOS byte (and therefore
+// ECN codepoint) the kernel observed on the inbound packet.
+// `cmsghdr` requires pointer-size alignment on Linux; a plain `[u8; N]`
+// stack array only guarantees 1-byte alignment, which makes the
+// `CMSG_FIRSTHDR`/`CMSG_DATA` dereferences below unaligned-reference UB.
+// Force the same alignment `libc::cmsghdr` itself needs.
+#[repr(align(8))]
+struct CmsgBuf([u8; 64]);
+
+fn recv_with_tos<S: AsRawFd>(socket: &S, buf: &mut [u8]) -> std::io::Result<(usize, IpTos)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cbuf = CmsgBuf([0u8; 64]);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cbuf.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cbuf.0.len();
+
+    let sz = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if sz < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
 
-        let spent = Instant::now() - start;
-        if spent > *timeout {
-            return Err(String::from("Timed out"));
-        }
-        nctx.socket
-            .set_read_timeout(Some(*timeout - spent))
-            .expect("Read timeout");
-        let sz = match nctx.socket.recv(&mut buf[..]) {
-            Ok(sz) => sz,
-            Err(e) => {
-                return Err(String::from(match e.kind() {
-                    std::io::ErrorKind::WouldBlock => "Timed out",
-                    _ => "Read error",
-                }));
+    let mut tos = IpTos::default();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let hdr = &*cmsg;
+            if hdr.cmsg_level == libc::IPPROTO_IP && hdr.cmsg_type == libc::IP_TOS {
+                tos = IpTos::from(*(libc::CMSG_DATA(cmsg) as *const u8));
             }
-        };
-
-        if sz == buf.len() {
-            eprintln!("Received more than {} bytes", buf.len());
-            continue;
-        }
-        if sz > 0 {
-            in_dgrams.push(Datagram::new(
-                nctx.remote_addr.clone(),
-                nctx.local_addr.clone(),
-                &buf[..sz],
-            ));
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
         }
     }
-}
 
-struct PreConnectHandler {}
-impl Handler for PreConnectHandler {
-    fn handle(&mut self, client: &mut Connection) -> bool {
-        match client.state() {
-            State::Connected => false,
-            State::Closing(..) => false,
-            _ => true,
-        }
-    }
+    Ok((sz as usize, tos))
 }
 
 // HTTP/0.9 IMPLEMENTATION
@@ -206,59 +243,20 @@ struct H3Handler {
     path: String,
 }
 
-// TODO(ekr@rtfm.com): Figure out how to merge this.
-fn process_loop_h3(
-    nctx: &NetworkCtx,
-    handler: &mut H3Handler,
-    timeout: &Duration,
-) -> Result<neqo_transport::connection::State, String> {
-    let buf = &mut [0u8; 2048];
-    let mut in_dgrams = Vec::new();
-    let start = Instant::now();
-
-    loop {
-        handler.h3.conn().process_input(in_dgrams.drain(..), now());
-
-        if let State::Closed(..) = handler.h3.conn().state() {
-            return Ok(handler.h3.conn().state().clone());
-        }
-
-        let exiting = !handler.handle();
-        let (out_dgrams, _timer) = handler.h3.conn().process_output(now());
-        emit_packets(&nctx.socket, &out_dgrams);
-
-        if exiting {
-            return Ok(handler.h3.conn().state().clone());
-        }
+// Shared by `H3Handler` and `WebTransportHandler`: access to the underlying
+// `Http3Connection` and one step of the handler's event processing.
+trait Http3Handler {
+    fn h3(&mut self) -> &mut Http3Connection;
+    fn handle(&mut self) -> bool;
+}
 
-        let spent = Instant::now() - start;
-        if spent > *timeout {
-            return Err(String::from("Timed out"));
-        }
-        nctx.socket
-            .set_read_timeout(Some(*timeout - spent))
-            .expect("Read timeout");
-        let sz = match nctx.socket.recv(&mut buf[..]) {
-            Ok(sz) => sz,
-            Err(e) => {
-                return Err(String::from(match e.kind() {
-                    std::io::ErrorKind::WouldBlock => "Timed out",
-                    _ => "Read error",
-                }));
-            }
-        };
+impl Http3Handler for H3Handler {
+    fn h3(&mut self) -> &mut Http3Connection {
+        &mut self.h3
+    }
 
-        if sz == buf.len() {
-            eprintln!("Received more than {} bytes", buf.len());
-            continue;
-        }
-        if sz > 0 {
-            in_dgrams.push(Datagram::new(
-                nctx.remote_addr.clone(),
-                nctx.local_addr.clone(),
-                &buf[..sz],
-            ));
-        }
+    fn handle(&mut self) -> bool {
+        H3Handler::handle(self)
     }
 }
 
@@ -307,10 +305,122 @@ impl H3Handler {
     }
 }
 
+// WEBTRANSPORT IMPLEMENTATION
+//
+// Analogous to `H3Handler`, but issues an extended-CONNECT (`:protocol =
+// webtransport`) through `Http3Connection` instead of a plain GET, then
+// echoes a payload over a WebTransport bidirectional stream.
+const WEBTRANSPORT_PAYLOAD: &[u8] = b"ping";
+
+struct WebTransportHandler {
+    h3: Http3Connection,
+    host: String,
+    path: String,
+    session_id: Option<u64>,
+    echo_stream_id: Option<u64>,
+    sent: bool,
+    echoed: bool,
+    // Bytes echoed back on `echo_stream_id` so far. A peer is free to split
+    // the echo across multiple `DataReadable` events, so each read is
+    // appended here rather than compared against the full payload alone.
+    echo_buf: Vec<u8>,
+}
+
+impl Http3Handler for WebTransportHandler {
+    fn h3(&mut self) -> &mut Http3Connection {
+        &mut self.h3
+    }
+
+    fn handle(&mut self) -> bool {
+        let mut data = vec![0; WEBTRANSPORT_PAYLOAD.len()];
+        self.h3.process_http3();
+        for event in self.h3.events() {
+            match event {
+                Http3Event::WebTransportSessionEstablished { session_id } => {
+                    println!("WebTransport session established: {}", session_id);
+                    self.session_id = Some(session_id);
+                    let stream_id = self
+                        .h3
+                        .create_webtransport_stream(session_id, StreamType::BiDi)
+                        .expect("Unable to open WebTransport stream");
+                    self.echo_stream_id = Some(stream_id);
+                }
+                Http3Event::DataReadable { stream_id } if Some(stream_id) == self.echo_stream_id => {
+                    let (sz, fin) = self
+                        .h3
+                        .read_data(stream_id, &mut data)
+                        .expect("Read should succeed");
+                    self.echo_buf.extend_from_slice(&data[..sz]);
+                    if self.echo_buf.len() >= WEBTRANSPORT_PAYLOAD.len() {
+                        self.echoed = self.echo_buf == WEBTRANSPORT_PAYLOAD;
+                    }
+                    if fin || self.echoed {
+                        if let Some(session_id) = self.session_id {
+                            self.h3.close_webtransport_session(session_id, 0, "kthxbye!");
+                        }
+                        return false;
+                    }
+                }
+                Http3Event::HeaderReady { stream_id } => {
+                    println!("READ HEADERS[{}]: {:?}", stream_id, self.h3.get_headers(stream_id));
+                }
+                _ => {}
+            }
+        }
+
+        if !self.sent {
+            if let Some(stream_id) = self.echo_stream_id {
+                self.h3
+                    .send_data(stream_id, WEBTRANSPORT_PAYLOAD)
+                    .expect("Write should succeed");
+                self.sent = true;
+            }
+        }
+
+        true
+    }
+}
+
+// Deserialized straight out of the `--peers` config file; `Peer` is built
+// from these so the rest of the harness never has to care whether a peer
+// came from disk or from `default_peers()`.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct PeerConfig {
+    label: String,
+    host: String,
+    port: u16,
+    // ALPN list to use instead of whatever `Test::alpn` would otherwise pick.
+    #[serde(default)]
+    alpn: Option<Vec<String>>,
+    // Base64-encoded ECHConfigList this peer advertises, if it supports ECH.
+    #[serde(default)]
+    ech: Option<String>,
+    // Test labels (see `Test::label`) this peer should *not* run.
+    #[serde(default)]
+    disabled_tests: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
 struct Peer {
-    label: &'static str,
-    host: &'static str,
+    label: String,
+    host: String,
     port: u16,
+    alpn: Option<Vec<String>>,
+    ech: Option<String>,
+    disabled_tests: Vec<String>,
+}
+
+impl From<PeerConfig> for Peer {
+    fn from(c: PeerConfig) -> Self {
+        Peer {
+            label: c.label,
+            host: c.host,
+            port: c.port,
+            alpn: c.alpn,
+            ech: c.ech,
+            disabled_tests: c.disabled_tests,
+        }
+    }
 }
 
 impl Peer {
@@ -328,8 +438,21 @@ impl Peer {
         }
     }
 
-    fn test_enabled(&self, _test: &Test) -> bool {
-        true
+    fn test_enabled(&self, test: &Test) -> bool {
+        !self.disabled_tests.contains(&test.label())
+    }
+
+    // The ALPN to offer for `test`, preferring a per-peer override over the
+    // test's own default (e.g. a peer on a non-standard draft version).
+    fn alpn_for(&self, test: &Test) -> Vec<String> {
+        self.alpn.clone().unwrap_or_else(|| test.alpn())
+    }
+
+    // The ECH config to use for this peer, preferring one configured on the
+    // peer itself and falling back to a global `--ech` override so the
+    // harness can be pointed at a local server without recompiling.
+    fn ech_config(&self, args: &Args) -> Option<String> {
+        self.ech.clone().or_else(|| args.ech.clone())
     }
 }
 
@@ -342,17 +465,110 @@ impl ToSocketAddrs for Peer {
     }
 }
 
+// The public interop grid, used when `--peers` isn't given.
+fn default_peers() -> Vec<Peer> {
+    vec![
+        Peer {
+            label: String::from("quant"),
+            host: String::from("quant.eggert.org"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("quicly"),
+            host: String::from("kazuhooku.com"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("local"),
+            host: String::from("127.0.0.1"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("applequic"),
+            host: String::from("192.168.203.142"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("f5"),
+            host: String::from("208.85.208.226"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("msft"),
+            host: String::from("quic.westus.cloudapp.azure.com"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("mvfst"),
+            host: String::from("fb.mvfst.net"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+        Peer {
+            label: String::from("google"),
+            host: String::from("quic.rocks"),
+            port: 4433,
+            alpn: None,
+            ech: None,
+            disabled_tests: Vec::new(),
+        },
+    ]
+}
+
+// Loads `--peers <path>` (a JSON array of `PeerConfig`) if given, otherwise
+// falls back to `default_peers()`.
+fn load_peers(args: &Args) -> Vec<Peer> {
+    let path = match &args.peers {
+        Some(path) => path,
+        None => return default_peers(),
+    };
+
+    let data = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e));
+    let configs: Vec<PeerConfig> = serde_json::from_str(&data)
+        .unwrap_or_else(|e| panic!("Unable to parse {}: {}", path.display(), e));
+    configs.into_iter().map(Peer::from).collect()
+}
+
 #[derive(Debug)]
 enum Test {
     Connect,
     H9,
     H3,
+    Resumption,
+    ZeroRtt,
+    Retry,
+    KeyUpdate,
+    Multiconnect,
+    WebTransport,
+    Ech,
+    Ecn,
 }
 
 impl Test {
     fn alpn(&self) -> Vec<String> {
         match self {
-            Test::H3 => vec![String::from("h3-20")],
+            Test::H3 | Test::WebTransport => vec![String::from("h3-20")],
             _ => vec![String::from("hq-20")],
         }
     }
@@ -362,236 +578,734 @@ impl Test {
             Test::Connect => "connect",
             Test::H9 => "h9",
             Test::H3 => "h3",
+            Test::Resumption => "resumption",
+            Test::ZeroRtt => "zerortt",
+            Test::Retry => "retry",
+            Test::KeyUpdate => "keyupdate",
+            Test::Multiconnect => "multiconnect",
+            Test::WebTransport => "webtransport",
+            Test::Ech => "ech",
+            Test::Ecn => "ecn",
         })
     }
 }
 
-struct NetworkCtx {
+// MULTIPLEXED REACTOR
+//
+// Every job's UDP socket is registered with a single `mio::Poll`, and
+// each job tracks its own next-wake `deadline` computed from the
+// `_timer` value `process_output` hands back. One thread services every
+// in-flight connection for every `Test` variant, instead of burning an
+// OS thread per test blocked in `socket.recv` with a read timeout.
+struct ReactorJob {
+    peer: Arc<Peer>,
+    test: &'static Test,
+    socket: mio::net::UdpSocket,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    socket: UdpSocket,
+    start: Instant,
+    timeout: Duration,
+    deadline: Instant,
+    state: JobState,
+}
+
+// Distinguishes the handful of `Test` variants that all ride on a single
+// HTTP/0.9 GET/response exchange, so they can share one `JobState` arm
+// and differ only in what `finish()` checks once the exchange is done.
+enum H9Mode {
+    Plain,
+    ZeroRtt,
+    KeyUpdate,
+    Ecn,
 }
 
-fn test_connect(nctx: &NetworkCtx, test: &Test, peer: &Peer) -> Result<(Connection), String> {
-    let mut client =
-        Connection::new_client(peer.host, test.alpn(), nctx.local_addr, nctx.remote_addr)
-            .expect("must succeed");
-    // Temporary here to help out the type inference engine
-    let mut h = PreConnectHandler {};
-    let res = process_loop(nctx, &mut client, &mut h, &Duration::new(5, 0));
+// How many sequential connections `Test::Multiconnect` makes before
+// reporting success.
+const MULTICONNECT_COUNT: usize = 5;
+
+enum JobState {
+    Connecting(Connection),
+    RunningH9(Connection, H9Handler, H9Mode),
+    RunningH3(H3Handler),
+    RunningWebTransport(WebTransportHandler),
+    // Second connection of `Test::Resumption`, set up with a resumption
+    // token taken from the first. Unlike `Connecting`, reaching
+    // `State::Connected` here finishes the job instead of starting a
+    // protocol exchange.
+    Resuming(Connection),
+    // `Test::Multiconnect`'s in-flight connection and how many prior
+    // connections (over the same socket) have already completed.
+    Multiconnecting(Connection, usize),
+    // Placeholder used while `tick` moves a connection out of one state and
+    // into whichever state comes next. When `tick` finishes a job straight
+    // out of `Connecting` (e.g. `Connect`, `Retry`, `Ech`), the connection
+    // has already been taken and the job is left in this state, with
+    // nothing for `process_output` to send.
+    Transitioning,
+}
 
-    let st = match res {
-        Ok(st) => st,
-        Err(e) => {
-            return Err(format!("ERROR: {}", e));
+impl ReactorJob {
+    fn new(peer: Arc<Peer>, test: &'static Test, args: &Args) -> Result<Self, String> {
+        let socket = UdpSocket::bind(peer.bind()).expect("Unable to bind UDP socket");
+        socket.connect(&*peer).expect("Unable to connect UDP socket");
+        socket
+            .set_nonblocking(true)
+            .expect("Unable to make socket non-blocking");
+        let local_addr = socket.local_addr().expect("Socket local address not bound");
+        let remote_addr = peer.addr();
+
+        let mut client =
+            Connection::new_client(&peer.host, peer.alpn_for(test), local_addr, remote_addr)
+                .expect("must succeed");
+        if let Some(ech) = peer.ech_config(args) {
+            let config = base64::decode(&ech).map_err(|e| format!("Bad --ech config: {}", e))?;
+            client
+                .client_enable_ech(&config)
+                .map_err(|e| format!("Unable to enable ECH: {:?}", e))?;
         }
-    };
 
-    match st {
-        State::Connected => Ok(client),
-        _ => Err(format!("{:?}", st)),
+        let now_inst = Instant::now();
+        Ok(ReactorJob {
+            peer,
+            test,
+            socket: mio::net::UdpSocket::from_std(socket),
+            local_addr,
+            remote_addr,
+            start: now_inst,
+            timeout: Duration::new(10, 0),
+            deadline: now_inst,
+            state: JobState::Connecting(client),
+        })
     }
-}
 
-fn test_h9(nctx: &NetworkCtx, client: &mut Connection) -> Result<(), String> {
-    let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
-    let req: String = "GET /10\r\n".to_string();
-    client
-        .stream_send(client_stream_id, req.as_bytes())
-        .unwrap();
-    let mut hc = H9Handler::default();
-    hc.streams.insert(client_stream_id);
-    let res = process_loop(nctx, client, &mut hc, &Duration::new(5, 0));
+    fn conn(&self) -> &Connection {
+        match &self.state {
+            JobState::Connecting(c) => c,
+            JobState::RunningH9(c, _, _) => c,
+            JobState::RunningH3(hc) => hc.h3.conn(),
+            JobState::RunningWebTransport(hc) => hc.h3.conn(),
+            JobState::Resuming(c) => c,
+            JobState::Multiconnecting(c, _) => c,
+            JobState::Transitioning => unreachable!("tick() never observes this state"),
+        }
+    }
 
-    match res {
-        Err(e) => {
-            return Err(format!("ERROR: {}", e));
+    fn process_input(&mut self, dgrams: Vec<Datagram>) {
+        match &mut self.state {
+            JobState::Connecting(c) => c.process_input(dgrams.into_iter(), now()),
+            JobState::RunningH9(c, _, _) => c.process_input(dgrams.into_iter(), now()),
+            JobState::RunningH3(hc) => hc.h3.conn().process_input(dgrams.into_iter(), now()),
+            JobState::RunningWebTransport(hc) => hc.h3.conn().process_input(dgrams.into_iter(), now()),
+            JobState::Resuming(c) => c.process_input(dgrams.into_iter(), now()),
+            JobState::Multiconnecting(c, _) => c.process_input(dgrams.into_iter(), now()),
+            JobState::Transitioning => unreachable!("tick() never observes this state"),
         }
-        _ => {}
-    };
+    }
 
-    if hc.rbytes == 0 {
-        return Err(String::from("Empty response"));
+    fn process_output(&mut self) -> (Vec<Datagram>, Option<Duration>) {
+        match &mut self.state {
+            JobState::Connecting(c) => c.process_output(now()),
+            JobState::RunningH9(c, _, _) => c.process_output(now()),
+            JobState::RunningH3(hc) => hc.h3.conn().process_output(now()),
+            JobState::RunningWebTransport(hc) => hc.h3.conn().process_output(now()),
+            JobState::Resuming(c) => c.process_output(now()),
+            JobState::Multiconnecting(c, _) => c.process_output(now()),
+            JobState::Transitioning => (Vec::new(), None),
+        }
     }
-    if !hc.rsfin {
-        return Err(String::from("No FIN"));
+
+    // Builds the second, token-bearing connection `Resumption`/`ZeroRtt`
+    // make after the first one (held in `client`) finishes its handshake.
+    fn resume(&self, client: &Connection, test: &Test) -> Result<Connection, String> {
+        let token = client
+            .resumption_token()
+            .ok_or_else(|| String::from("No resumption token"))?;
+        let mut client2 =
+            Connection::new_client(&self.peer.host, self.peer.alpn_for(test), self.local_addr, self.remote_addr)
+                .expect("must succeed");
+        client2
+            .set_resumption_token(token)
+            .map_err(|e| format!("Unable to set resumption token: {:?}", e))?;
+        Ok(client2)
     }
-    Ok(())
-}
 
-fn test_h3(nctx: &NetworkCtx, peer: &Peer, client: Connection) -> Result<(), String> {
-    let mut hc = H3Handler {
-        streams: HashSet::new(),
-        h3: Http3Connection::new(client, 128, 128),
-        host: String::from(peer.host.clone()),
-        path: String::from("/"),
-    };
+    // Advance the job one step. Returns `Some(result)` once it is done
+    // (successfully or not); `None` means it is still in flight.
+    fn tick(&mut self) -> Option<String> {
+        if let State::Closed(..) = self.conn().state() {
+            return Some(self.finish());
+        }
 
-    let client_stream_id = hc
-        .h3
-        .fetch("GET", "https", &hc.host, &hc.path, &vec![])
-        .unwrap();
+        match &mut self.state {
+            JobState::Connecting(c) => match c.state() {
+                State::Connected => {
+                    let mut client = match std::mem::replace(&mut self.state, JobState::Transitioning) {
+                        JobState::Connecting(c) => c,
+                        _ => unreachable!(),
+                    };
+
+                    match self.test {
+                        Test::Connect => return Some(String::from("OK")),
+                        Test::Retry => {
+                            return Some(if client.retry_sent() {
+                                String::from("OK")
+                            } else {
+                                String::from("No retry/token round-trip observed")
+                            });
+                        }
+                        Test::Ech => {
+                            return Some(if client.ech_accepted() {
+                                String::from("OK")
+                            } else if client.ech_retry_config().is_some() {
+                                String::from("ECH rejected (retry-config offered)")
+                            } else {
+                                String::from("ECH rejected (no retry-config)")
+                            });
+                        }
+                        Test::H9 => {
+                            let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+                            client.stream_send(stream_id, b"GET /10\r\n").unwrap();
+                            let mut handler = H9Handler::default();
+                            handler.streams.insert(stream_id);
+                            self.state = JobState::RunningH9(client, handler, H9Mode::Plain);
+                        }
+                        Test::KeyUpdate => {
+                            let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+                            client
+                                .stream_send(stream_id, b"GET /1000000\r\n")
+                                .unwrap();
+                            if let Err(e) = client.initiate_key_update() {
+                                return Some(format!("Unable to initiate key update: {:?}", e));
+                            }
+                            let mut handler = H9Handler::default();
+                            handler.streams.insert(stream_id);
+                            self.state = JobState::RunningH9(client, handler, H9Mode::KeyUpdate);
+                        }
+                        Test::Ecn => {
+                            // ECT(0); the low two bits of the TOS byte are
+                            // the ECN codepoint.
+                            const ECT0: u8 = 0x02;
+                            set_outgoing_ecn(&self.socket, IpTos::from(ECT0));
+                            enable_ecn_reporting(&self.socket);
+                            let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+                            client.stream_send(stream_id, b"GET /10\r\n").unwrap();
+                            let mut handler = H9Handler::default();
+                            handler.streams.insert(stream_id);
+                            self.state = JobState::RunningH9(client, handler, H9Mode::Ecn);
+                        }
+                        Test::H3 => {
+                            let mut hc = H3Handler {
+                                streams: HashSet::new(),
+                                h3: Http3Connection::new(client, 128, 128),
+                                host: self.peer.host.clone(),
+                                path: String::from("/"),
+                            };
+                            let stream_id = hc
+                                .h3
+                                .fetch("GET", "https", &hc.host, &hc.path, &vec![])
+                                .unwrap();
+                            hc.streams.insert(stream_id);
+                            self.state = JobState::RunningH3(hc);
+                        }
+                        Test::WebTransport => {
+                            let mut hc = WebTransportHandler {
+                                h3: Http3Connection::new(client, 128, 128),
+                                host: self.peer.host.clone(),
+                                path: String::from("/webtransport"),
+                                session_id: None,
+                                echo_stream_id: None,
+                                sent: false,
+                                echoed: false,
+                                echo_buf: Vec::new(),
+                            };
+                            hc.h3
+                                .connect_webtransport(&hc.host, &hc.path)
+                                .expect("Unable to issue extended-CONNECT");
+                            self.state = JobState::RunningWebTransport(hc);
+                        }
+                        Test::Resumption => match self.resume(&client, &Test::Resumption) {
+                            Ok(client2) => self.state = JobState::Resuming(client2),
+                            Err(e) => return Some(e),
+                        },
+                        Test::ZeroRtt => match self.resume(&client, &Test::ZeroRtt) {
+                            Ok(mut client2) => {
+                                let stream_id = client2.stream_create(StreamType::BiDi).unwrap();
+                                client2.stream_send(stream_id, b"GET /10\r\n").unwrap();
+                                let mut handler = H9Handler::default();
+                                handler.streams.insert(stream_id);
+                                self.state = JobState::RunningH9(client2, handler, H9Mode::ZeroRtt);
+                            }
+                            Err(e) => return Some(e),
+                        },
+                        Test::Multiconnect => {
+                            let completed = 1;
+                            if completed >= MULTICONNECT_COUNT {
+                                return Some(String::from("OK"));
+                            }
+                            let next = Connection::new_client(
+                                &self.peer.host,
+                                self.peer.alpn_for(self.test),
+                                self.local_addr,
+                                self.remote_addr,
+                            )
+                            .expect("must succeed");
+                            self.state = JobState::Multiconnecting(next, completed);
+                        }
+                    }
+                    None
+                }
+                State::Closing(..) => Some(self.finish()),
+                _ => None,
+            },
+            JobState::RunningH9(c, handler, _) => {
+                if !handler.handle(c) {
+                    Some(self.finish())
+                } else {
+                    None
+                }
+            }
+            JobState::RunningH3(hc) => {
+                if !hc.handle() {
+                    Some(self.finish())
+                } else {
+                    None
+                }
+            }
+            JobState::RunningWebTransport(hc) => {
+                if !hc.handle() {
+                    Some(self.finish())
+                } else {
+                    None
+                }
+            }
+            JobState::Resuming(c) => match c.state() {
+                State::Connected => Some(if c.tls_info().map_or(false, |i| i.resumed()) {
+                    String::from("OK")
+                } else {
+                    String::from("Connected, but not resumed")
+                }),
+                State::Closing(..) => Some(self.finish()),
+                _ => None,
+            },
+            JobState::Multiconnecting(c, completed) => match c.state() {
+                State::Connected => {
+                    let completed = *completed + 1;
+                    if completed >= MULTICONNECT_COUNT {
+                        return Some(String::from("OK"));
+                    }
+                    let next = Connection::new_client(
+                        &self.peer.host,
+                        self.peer.alpn_for(self.test),
+                        self.local_addr,
+                        self.remote_addr,
+                    )
+                    .expect("must succeed");
+                    self.state = JobState::Multiconnecting(next, completed);
+                    None
+                }
+                State::Closing(..) => Some(self.finish()),
+                _ => None,
+            },
+            JobState::Transitioning => unreachable!("tick() never observes this state"),
+        }
+    }
 
-    hc.streams.insert(client_stream_id);
-    let res = process_loop_h3(nctx, &mut hc, &Duration::new(5, 0));
-    match res {
-        Err(e) => {
-            return Err(format!("ERROR: {}", e));
+    fn finish(&self) -> String {
+        match &self.state {
+            JobState::Connecting(c) => format!("{:?}", c.state()),
+            JobState::RunningH9(c, handler, mode) => {
+                if handler.rbytes == 0 {
+                    return String::from("Empty response");
+                }
+                match mode {
+                    H9Mode::Plain => {
+                        if !handler.rsfin {
+                            String::from("No FIN")
+                        } else {
+                            String::from("OK")
+                        }
+                    }
+                    H9Mode::ZeroRtt => {
+                        if !handler.rsfin {
+                            String::from("No FIN")
+                        } else if !c.tls_info().map_or(false, |i| i.early_data_accepted()) {
+                            String::from("Response received, but 0-RTT was not accepted")
+                        } else {
+                            String::from("OK")
+                        }
+                    }
+                    H9Mode::KeyUpdate => {
+                        if !handler.rsfin {
+                            String::from("No FIN (key update broke the stream)")
+                        } else {
+                            String::from("OK")
+                        }
+                    }
+                    H9Mode::Ecn => {
+                        let (ect0, ect1, ce) = c.ecn_counts();
+                        if ect0 == 0 && ect1 == 0 && ce == 0 {
+                            String::from("No ECN counts observed in peer's ACKs (path did not preserve ECN)")
+                        } else {
+                            String::from("OK")
+                        }
+                    }
+                }
+            }
+            JobState::RunningH3(_) => String::from("OK"),
+            JobState::RunningWebTransport(hc) => {
+                if hc.echoed {
+                    String::from("OK")
+                } else {
+                    String::from("WebTransport echo not observed")
+                }
+            }
+            JobState::Resuming(c) => format!("{:?}", c.state()),
+            JobState::Multiconnecting(c, completed) => {
+                format!("Connection {} of {}: {:?}", completed + 1, MULTICONNECT_COUNT, c.state())
+            }
+            JobState::Transitioning => unreachable!("tick() never observes this state"),
         }
-        _ => {}
-    };
+    }
+}
 
-    Ok(())
+fn emit_packets_mio(socket: &mio::net::UdpSocket, out_dgrams: &Vec<Datagram>) {
+    for d in out_dgrams {
+        let sent = socket.send(&d[..]).expect("Error sending datagram");
+        if sent != d.len() {
+            eprintln!("Unable to send all {} bytes of datagram", d.len());
+        }
+    }
 }
 
-fn run_test<'t>(peer: &Peer, test: &'t Test) -> (&'t Test, String) {
-    let socket = UdpSocket::bind(peer.bind()).expect("Unable to bind UDP socket");
-    socket.connect(&peer).expect("Unable to connect UDP socket");
+// Drive every `(peer, test)` pair to completion concurrently on this
+// thread, using one `mio::Poll` for every UDP socket involved.
+fn run_matrix(
+    args: &Args,
+    work: Vec<(Arc<Peer>, &'static Test)>,
+) -> Vec<(Arc<Peer>, &'static Test, String)> {
+    let mut poll = Poll::new().expect("Unable to create mio poll");
+    let mut jobs: std::collections::HashMap<Token, ReactorJob> = std::collections::HashMap::new();
+
+    for (i, (peer, test)) in work.into_iter().enumerate() {
+        let token = Token(i);
+        match ReactorJob::new(peer, test, args) {
+            Ok(mut job) => {
+                poll.registry()
+                    .register(&mut job.socket, token, Interest::READABLE)
+                    .expect("Unable to register socket");
+                jobs.insert(token, job);
+            }
+            Err(e) => {
+                eprintln!("Unable to start {}/{}: {}", peer.label, test.label(), e);
+            }
+        }
+    }
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
-    let remote_addr = peer.addr();
+    let mut events = Events::with_capacity(jobs.len().max(1));
+    let mut results = Vec::new();
+
+    while !jobs.is_empty() {
+        let timeout = jobs
+            .values()
+            .map(|j| j.deadline.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or_else(|| Duration::from_millis(100));
+        poll.poll(&mut events, Some(timeout))
+            .expect("mio poll failed");
+
+        let mut ready: HashSet<Token> = events.iter().map(|e| e.token()).collect();
+        let now_inst = Instant::now();
+        for (token, job) in jobs.iter() {
+            if now_inst >= job.deadline {
+                ready.insert(*token);
+            }
+        }
 
-    let nctx = NetworkCtx {
-        socket: socket,
-        local_addr: local_addr,
-        remote_addr: remote_addr,
-    };
+        let mut done = Vec::new();
+        for token in ready {
+            let job = match jobs.get_mut(&token) {
+                Some(j) => j,
+                None => continue,
+            };
+
+            let mut in_dgrams = Vec::new();
+            let mut buf = [0u8; 2048];
+            loop {
+                match recv_with_tos(&job.socket, &mut buf[..]) {
+                    Ok((sz, tos)) if sz > 0 => {
+                        in_dgrams.push(Datagram::new(
+                            job.remote_addr,
+                            job.local_addr,
+                            tos,
+                            &buf[..sz],
+                        ))
+                    }
+                    Ok(_) => break,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        eprintln!("Read error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            job.process_input(in_dgrams);
 
-    let mut client = match test_connect(&nctx, test, peer) {
-        Ok(client) => client,
-        Err(e) => return (test, e),
-    };
+            let outcome = job.tick();
+            let (out_dgrams, timer) = job.process_output();
+            emit_packets_mio(&job.socket, &out_dgrams);
+
+            if Instant::now() - job.start > job.timeout {
+                done.push((token, String::from("Timed out")));
+                continue;
+            }
 
-    let res = match test {
-        Test::Connect => {
-            return (test, String::from("OK"));
+            match outcome {
+                Some(result) => done.push((token, result)),
+                None => {
+                    job.deadline = Instant::now() + timer.unwrap_or(Duration::from_millis(100));
+                }
+            }
         }
-        Test::H9 => test_h9(&nctx, &mut client),
-        Test::H3 => test_h3(&nctx, peer, client),
-    };
 
-    match res {
-        Ok(_) => {}
-        Err(e) => return (test, e),
+        for (token, result) in done {
+            if let Some(mut job) = jobs.remove(&token) {
+                let _ = poll.registry().deregister(&mut job.socket);
+                results.push((job.peer, job.test, result));
+            }
+        }
     }
 
-    match test {
-        _ => {
-            return (test, String::from("OK"));
-        }
-    };
+    results
 }
 
-fn run_peer(args: &Args, peer: &'static Peer) -> Vec<(&'static Test, String)> {
-    let mut results: Vec<(&'static Test, String)> = Vec::new();
+// SERVER MODE
+//
+// Stands up the responder side of the interop matrix: bind `--listen`,
+// drive a `neqo_transport::server::Server` for connection acceptance and
+// an `Http3Server` on top of it to answer `fetch`-style GETs.  Each
+// connection's final state is printed when it closes, one line per
+// connection, as `main` prints one line per `run_matrix` result for the
+// client side.
+
+const SERVER_ALPN: &[&str] = &["h3-20", "hq-20"];
+
+// The largest body this responder will hand out for a `GET /N` request.
+// `N` comes straight off the wire, so without a cap any peer can force a
+// multi-gigabyte allocation with a single request.
+const MAX_H9_BODY: usize = 16 * 1024 * 1024;
+
+// A fixed-size body generator matching the `/N` HTTP/0.9 convention the
+// client side of this binary already speaks in `ReactorJob::tick`.
+fn body_of_size(n: usize) -> Vec<u8> {
+    vec![b'a'; n.min(MAX_H9_BODY)]
+}
 
-    eprintln!("Running tests for {}", peer.label);
+fn parse_h9_request(req: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(req).ok()?;
+    let path = line.trim().strip_prefix("GET /")?;
+    path.trim_end_matches('\r').parse().ok()
+}
 
-    let mut children = Vec::new();
+// Holds the `ActiveConnectionRef` the `Server` itself keeps driving, rather
+// than a detached clone: every datagram for this peer after the handshake
+// lands in `server.process()`'s own connection state, so anything other
+// than this shared handle would never see a post-handshake GET.
+struct H9Server {
+    connection: ActiveConnectionRef,
+    streams: HashSet<u64>,
+    // `GET /N\r\n` bytes seen so far per stream, since a peer's QUIC stack
+    // is free to split the request line across more than one STREAM frame.
+    pending: std::collections::HashMap<u64, Vec<u8>>,
+}
 
-    for test in &TESTS {
-        if !peer.test_enabled(&test) {
-            continue;
+impl H9Server {
+    fn handle(&mut self) {
+        let mut data = vec![0; 4000];
+        let mut conn = self.connection.borrow_mut();
+        for event in conn.events() {
+            match event {
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    self.streams.insert(stream_id);
+                    let (sz, _fin) = match conn.stream_recv(stream_id, &mut data) {
+                        Ok(r) => r,
+                        Err(_) => continue,
+                    };
+                    let buf = self.pending.entry(stream_id).or_insert_with(Vec::new);
+                    buf.extend_from_slice(&data[..sz]);
+                    if !buf.contains(&b'\n') {
+                        continue;
+                    }
+                    let line = self.pending.remove(&stream_id).unwrap();
+                    if let Some(n) = parse_h9_request(&line) {
+                        let body = body_of_size(n);
+                        let _ = conn.stream_send(stream_id, &body);
+                        let _ = conn.stream_close_send(stream_id);
+                    }
+                }
+                _ => {}
+            }
         }
+    }
+}
 
-        if args.include_tests.len() > 0 && !args.include_tests.contains(&test.label()) {
-            continue;
-        }
-        if args.exclude_tests.contains(&test.label()) {
-            continue;
-        }
+enum ServerConn {
+    H9(H9Server),
+    H3(Http3Server),
+}
 
-        let child = thread::spawn(move || run_test(peer, test));
-        children.push((test, child));
-    }
+// Server counterpart of `run_matrix`: drives a single UDP socket shared by
+// every accepted connection, polling it with a 100ms read timeout.
+fn server_loop(socket: &UdpSocket, server: &mut Server) -> ! {
+    let buf = &mut [0u8; 2048];
+    let mut conns: std::collections::HashMap<SocketAddr, ServerConn> =
+        std::collections::HashMap::new();
 
-    for child in children {
-        match child.1.join() {
-            Ok(e) => {
-                eprintln!("Test complete {:?}, {:?}", child.0, e);
-                results.push(e)
+    loop {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .expect("Read timeout");
+        let (sz, remote) = match socket.recv_from(&mut buf[..]) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (0, socket.local_addr().unwrap()),
+            Err(e) => {
+                eprintln!("Read error: {:?}", e);
+                continue;
             }
-            Err(_) => {
-                eprintln!("Thread crashed {:?}", child.0);
-                results.push((child.0, String::from("CRASHED")));
+        };
+
+        let local_addr = socket.local_addr().expect("Socket local address not bound");
+        let dgrams = if sz > 0 {
+            vec![Datagram::new(remote, local_addr, IpTos::default(), &buf[..sz])]
+        } else {
+            vec![]
+        };
+
+        // Every `ServerEvent` carries the `ActiveConnectionRef` it actually
+        // pertains to; a batch can mix events for several peers, so the
+        // connection's own remote address -- not the `remote` of whichever
+        // packet was just read -- is what keys `conns`.
+        let mut touched: HashSet<SocketAddr> = HashSet::new();
+        for event in server.process(dgrams, now()) {
+            match event {
+                ServerEvent::ConnectionCreated { connection } => {
+                    let peer = connection.borrow().remote_addr();
+                    eprintln!("New connection from {}", peer);
+                    let alpn = connection.borrow().alpn().map(String::from);
+                    let ctx = match alpn.as_deref() {
+                        Some("h3-20") => ServerConn::H3(Http3Server::new(connection, 128, 128)),
+                        _ => ServerConn::H9(H9Server {
+                            connection,
+                            streams: HashSet::new(),
+                            pending: std::collections::HashMap::new(),
+                        }),
+                    };
+                    touched.insert(peer);
+                    conns.insert(peer, ctx);
+                }
+                ServerEvent::ConnectionClosed { connection } => {
+                    let peer = connection.borrow().remote_addr();
+                    eprintln!("{} -> {:?}", peer, connection.borrow().state());
+                    conns.remove(&peer);
+                }
+                _ => {}
+            }
+        }
+        if sz > 0 {
+            touched.insert(remote);
+        }
+
+        for peer in touched {
+            if let Some(ctx) = conns.get_mut(&peer) {
+                match ctx {
+                    ServerConn::H9(h9) => h9.handle(),
+                    ServerConn::H3(h3) => {
+                        h3.process_http3();
+                        for event in h3.events() {
+                            if let Http3ServerEvent::Headers { stream_id, .. } = event {
+                                let body = body_of_size(10);
+                                let _ = h3.send_data(stream_id, &body);
+                                let _ = h3.stream_close_send(stream_id);
+                            }
+                        }
+                    }
+                }
             }
         }
+
+        let (out_dgrams, _timer) = server.process_output(now());
+        emit_packets(socket, &out_dgrams);
     }
+}
 
-    println!("Tests for {} complete {:?}", peer.label, results);
-    results
+fn run_server(args: &Args) {
+    let addr = args.listen.expect("run_server called without --listen");
+    init_db(args.db.clone());
+
+    let socket = UdpSocket::bind(addr).expect("Unable to bind UDP socket");
+    eprintln!("Listening on {}", addr);
+
+    let mut server = Server::new(
+        now(),
+        &[args.cert.clone()],
+        SERVER_ALPN.iter().map(|a| a.to_string()).collect(),
+    )
+    .expect("Unable to create server");
+
+    server_loop(&socket, &mut server);
 }
 
-const PEERS: [Peer; 8] = [
-    Peer {
-        label: &"quant",
-        host: &"quant.eggert.org",
-        port: 4433,
-    },
-    Peer {
-        label: &"quicly",
-        host: "kazuhooku.com",
-        port: 4433,
-    },
-    Peer {
-        label: &"local",
-        host: &"127.0.0.1",
-        port: 4433,
-    },
-    Peer {
-        label: &"applequic",
-        host: &"192.168.203.142",
-        port: 4433,
-    },
-    Peer {
-        label: &"f5",
-        host: &"208.85.208.226",
-        port: 4433,
-    },
-    Peer {
-        label: &"msft",
-        host: &"quic.westus.cloudapp.azure.com",
-        port: 4433,
-    },
-    Peer {
-        label: &"mvfst",
-        host: &"fb.mvfst.net",
-        port: 4433,
-    },
-    Peer {
-        label: &"google",
-        host: &"quic.rocks",
-        port: 4433,
-    },
+const TESTS: [Test; 11] = [
+    Test::Connect,
+    Test::H9,
+    Test::H3,
+    Test::Resumption,
+    Test::ZeroRtt,
+    Test::Retry,
+    Test::KeyUpdate,
+    Test::Multiconnect,
+    Test::WebTransport,
+    Test::Ech,
+    Test::Ecn,
 ];
 
-const TESTS: [Test; 3] = [Test::Connect, Test::H9, Test::H3];
-
 fn main() {
-    let _tests = vec![Test::Connect];
-
     let args = Args::from_args();
     init();
 
-    let mut children = Vec::new();
+    if args.listen.is_some() {
+        run_server(&args);
+        return;
+    }
 
-    // Start all the children.
-    for peer in &PEERS {
-        if args.include.len() > 0 && !args.include.contains(&String::from(peer.label)) {
-            continue;
-        }
-        if args.exclude.contains(&String::from(peer.label)) {
-            continue;
+    let selected_peers: Vec<Arc<Peer>> = load_peers(&args)
+        .into_iter()
+        .filter(|peer| {
+            if args.include.len() > 0 && !args.include.contains(&peer.label) {
+                return false;
+            }
+            !args.exclude.contains(&peer.label)
+        })
+        .map(Arc::new)
+        .collect();
+
+    // Every test for every selected peer runs concurrently through one
+    // shared mio reactor instead of a thread per test.
+    let mut matrix_work = Vec::new();
+    for peer in &selected_peers {
+        for test in &TESTS {
+            if !peer.test_enabled(test) {
+                continue;
+            }
+            if args.include_tests.len() > 0 && !args.include_tests.contains(&test.label()) {
+                continue;
+            }
+            if args.exclude_tests.contains(&test.label()) {
+                continue;
+            }
+            matrix_work.push((Arc::clone(peer), test));
         }
-
-        let at = args.clone();
-        let child = thread::spawn(move || run_peer(&at, &peer));
-        children.push((peer, child));
     }
-
-    // Now wait for them.
-    for child in children {
-        let res = child.1.join().unwrap();
-        eprintln!("{} -> {:?}", child.0.label, res);
+    for (peer, test, result) in run_matrix(&args, matrix_work) {
+        eprintln!("{} -> {:?} {}", peer.label, test, result);
     }
 }